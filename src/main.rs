@@ -1,17 +1,31 @@
 use std::{
     collections::HashMap,
     env, format, fs,
-    io::{BufRead, BufReader, Write},
+    io::{BufReader, Read, Write},
     net::{TcpListener, TcpStream},
     println, thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{anyhow, Context, Result};
+use flate2::{write::DeflateEncoder, write::GzEncoder, Compression};
 use itertools::Itertools;
 
+/// Maximum size of the request line + headers, to bound memory use against a
+/// peer that never sends a terminating blank line.
+const MAX_HEADER_SECTION_BYTES: usize = 8192;
+const MAX_REQUEST_HEADERS: usize = 64;
+/// Maximum size of a request body, to bound memory use against a peer that
+/// sends an unreasonably large `Content-Length`.
+const MAX_BODY_BYTES: usize = 10 * 1024 * 1024;
+
 const CODE_200_OK: &str = "200 OK";
+const CODE_201_CREATED: &str = "201 Created";
+const CODE_206_PARTIAL_CONTENT: &str = "206 Partial Content";
+const CODE_304_NOT_MODIFIED: &str = "304 Not Modified";
 const CODE_400_BAD_REQUEST: &str = "400 Bad Request";
 const CODE_404_NOT_FOUND: &str = "404 Not Found";
+const CODE_416_RANGE_NOT_SATISFIABLE: &str = "416 Range Not Satisfiable";
 const CODE_500_INTERNAL_SERVER_ERROR: &str = "500 Internal Server Error";
 
 #[derive(Default, Clone)]
@@ -35,11 +49,31 @@ enum HttpMethod {
     Post,
 }
 
+/// Request headers in arrival order, preserving repeated fields (e.g.
+/// multiple `Set-Cookie`-style headers) and looking names up
+/// case-insensitively, per RFC 7230.
+#[derive(Default, Debug, Clone)]
+struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    fn insert(&mut self, name: String, value: String) {
+        self.0.push((name, value));
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
 struct HttpRequest {
     method: HttpMethod,
     path: String,
     version: String,
-    headers: HashMap<String, String>,
+    headers: Headers,
+    body: Vec<u8>,
 }
 
 #[derive(Debug, Default)]
@@ -70,6 +104,15 @@ impl HttpResponse {
         self
     }
 
+    fn with_html_content(mut self, content: &str) -> HttpResponse {
+        self.content = content.bytes().collect_vec();
+        self.headers
+            .insert("Content-Type".to_owned(), "text/html".to_owned());
+        self.headers
+            .insert("Content-Length".to_owned(), content.len().to_string());
+        self
+    }
+
     fn with_binary_content(mut self, content: Vec<u8>) -> HttpResponse {
         self.headers.insert(
             "Content-Type".to_owned(),
@@ -77,16 +120,266 @@ impl HttpResponse {
         );
         self.headers
             .insert("Content-Length".to_owned(), content.len().to_string());
+        self.headers
+            .insert("Accept-Ranges".to_owned(), "bytes".to_owned());
         self.content = content;
         self
     }
+
+    fn with_partial_content(
+        mut self,
+        content: Vec<u8>,
+        start: usize,
+        end: usize,
+        total: usize,
+    ) -> HttpResponse {
+        self.headers.insert(
+            "Content-Type".to_owned(),
+            "application/octet-stream".to_owned(),
+        );
+        self.headers
+            .insert("Content-Length".to_owned(), content.len().to_string());
+        self.headers.insert(
+            "Content-Range".to_owned(),
+            format!("bytes {start}-{end}/{total}"),
+        );
+        self.content = content;
+        self
+    }
+
+    /// Compresses `content` with `encoding` ("gzip" or "deflate"), updating
+    /// `Content-Encoding` and `Content-Length` accordingly. Unknown codings,
+    /// a `None` negotiation, or a response with no body (e.g. `304 Not
+    /// Modified`, which must never gain one) leave the response untouched.
+    fn with_encoding(mut self, encoding: Option<&str>) -> HttpResponse {
+        if self.content.is_empty() {
+            return self;
+        }
+        let compressed = match encoding {
+            Some("gzip") => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&self.content).ok();
+                encoder.finish().ok()
+            }
+            Some("deflate") => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&self.content).ok();
+                encoder.finish().ok()
+            }
+            _ => None,
+        };
+        if let Some(compressed) = compressed {
+            self.headers
+                .insert("Content-Length".to_owned(), compressed.len().to_string());
+            self.headers
+                .insert("Content-Encoding".to_owned(), encoding.unwrap().to_owned());
+            self.content = compressed;
+        }
+        self
+    }
+}
+
+/// Picks the first encoding in the client's `Accept-Encoding` list that this
+/// server knows how to produce, ignoring unsupported codings.
+fn negotiate_encoding(req: &HttpRequest) -> Option<&'static str> {
+    let accept_encoding = req.headers.get("Accept-Encoding")?;
+    accept_encoding
+        .split(',')
+        .map(str::trim)
+        .find_map(|coding| match coding {
+            "gzip" => Some("gzip"),
+            "deflate" => Some("deflate"),
+            _ => None,
+        })
+}
+
+/// Parses a `Range: bytes=...` header value into an inclusive `(start, end)`
+/// byte range against a resource of `total` bytes. Returns `None` when the
+/// range is malformed or unsatisfiable for `total`.
+fn parse_byte_range(value: &str, total: usize) -> Option<(usize, usize)> {
+    let spec = value.strip_prefix("bytes=")?;
+    let (start, end) = spec.split_once('-')?;
+    if start.is_empty() {
+        let suffix: usize = end.parse().ok()?;
+        if suffix == 0 || total == 0 {
+            return None;
+        }
+        let suffix = suffix.min(total);
+        Some((total - suffix, total - 1))
+    } else {
+        let start: usize = start.parse().ok()?;
+        if start >= total {
+            return None;
+        }
+        let end = match end.is_empty() {
+            true => total - 1,
+            false => end.parse::<usize>().ok()?.min(total - 1),
+        };
+        if start > end {
+            return None;
+        }
+        Some((start, end))
+    }
+}
+
+/// Decodes `%XX` percent-escapes in a URL path into their raw bytes.
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Ok(byte) = u8::from_str_radix(&path[i + 1..i + 3], 16) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Renders an HTML index page listing the entries of `full_path`, a
+/// directory reached under `/files/<subpath>`. Subdirectories get a
+/// trailing slash so nested navigation keeps working.
+fn directory_index_html(full_path: &str, subpath: &str) -> String {
+    let mut entries = fs::read_dir(full_path)
+        .map(|rd| rd.filter_map(Result::ok).collect_vec())
+        .unwrap_or_default();
+    entries.sort_by_key(|entry| entry.file_name());
+    let base = subpath.trim_end_matches('/');
+    let rows = entries
+        .iter()
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+            let suffix = if is_dir { "/" } else { "" };
+            let href = if base.is_empty() {
+                format!("/files/{name}{suffix}")
+            } else {
+                format!("/files/{base}/{name}{suffix}")
+            };
+            let href = escape_html(&href);
+            let text = escape_html(&format!("{name}{suffix}"));
+            format!("<li><a href=\"{href}\">{text}</a></li>")
+        })
+        .join("\n");
+    format!("<html><body><ul>\n{rows}\n</ul></body></html>")
+}
+
+/// Escapes the characters HTML gives special meaning to, so untrusted text
+/// (e.g. a filename from `fs::read_dir`) can be interpolated into markup
+/// without letting it break out of an attribute or tag.
+fn escape_html(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_owned(),
+            '<' => "&lt;".to_owned(),
+            '>' => "&gt;".to_owned(),
+            '"' => "&quot;".to_owned(),
+            '\'' => "&#39;".to_owned(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+const WEEKDAY_NAMES: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTH_NAMES: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Howard Hinnant's `civil_from_days`: converts a day count since the Unix
+/// epoch into a (year, month, day) civil calendar date.
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097; // [0, 146096]
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365; // [0, 399]
+    let y = yoe + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100); // [0, 365]
+    let mp = (5 * doy + 2) / 153; // [0, 11]
+    let d = doy - (153 * mp + 2) / 5 + 1; // [1, 31]
+    let m = mp + if mp < 10 { 3 } else { -9 }; // [1, 12]
+    let y = if m <= 2 { y + 1 } else { y };
+    (y, m as u32, d as u32)
+}
+
+/// Inverse of [`civil_from_days`]: the day count since the Unix epoch for a
+/// (year, month, day) civil calendar date.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let m = month as i64;
+    let d = day as i64;
+    let y = if m <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400; // [0, 399]
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1; // [0, 365]
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy; // [0, 146096]
+    era * 146097 + doe - 719468
+}
+
+/// Formats a timestamp as an RFC 1123 HTTP-date, e.g.
+/// `"Sun, 06 Nov 1994 08:49:37 GMT"`.
+fn format_http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let days = secs.div_euclid(86400);
+    let time_of_day = secs.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let weekday = WEEKDAY_NAMES[(days + 4).rem_euclid(7) as usize];
+    let month_name = MONTH_NAMES[(month - 1) as usize];
+    format!(
+        "{weekday}, {day:02} {month_name} {year} {:02}:{:02}:{:02} GMT",
+        time_of_day / 3600,
+        (time_of_day % 3600) / 60,
+        time_of_day % 60,
+    )
+}
+
+/// Truncates a timestamp down to whole seconds, the resolution an HTTP-date
+/// can represent, so it compares equal to one round-tripped through
+/// [`format_http_date`]/[`parse_http_date`].
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Parses an RFC 1123 HTTP-date, e.g. `"Sun, 06 Nov 1994 08:49:37 GMT"`.
+/// Returns `None` when the day, month, year or time of day can't be read.
+/// Fields are taken by position: the weekday name is not checked, and
+/// anything after the time of day (normally `GMT`) is ignored.
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let (_, rest) = value.split_once(", ")?;
+    let mut parts = rest.split_whitespace();
+    let day: u32 = parts.next()?.parse().ok()?;
+    let month_token = parts.next()?;
+    let month = MONTH_NAMES.iter().position(|m| *m == month_token)? as u32 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut time_parts = parts.next()?.split(':');
+    let hour: i64 = time_parts.next()?.parse().ok()?;
+    let minute: i64 = time_parts.next()?.parse().ok()?;
+    let second: i64 = time_parts.next()?.parse().ok()?;
+
+    let days = days_from_civil(year, month, day);
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+    let secs = u64::try_from(secs).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
 }
 
 fn handle(req: HttpRequest, config: &Config) -> HttpResponse {
-    match req.method {
+    let encoding = negotiate_encoding(&req);
+    let rsp = match req.method {
         HttpMethod::Get => handle_get(req, config),
-        HttpMethod::Post => handle_post(req),
-    }
+        HttpMethod::Post => handle_post(req, config),
+    };
+    rsp.with_encoding(encoding)
 }
 
 fn handle_get(req: HttpRequest, config: &Config) -> HttpResponse {
@@ -110,10 +403,28 @@ fn handle_get(req: HttpRequest, config: &Config) -> HttpResponse {
     rsp.with_code(CODE_404_NOT_FOUND)
 }
 
-fn handle_post(req: HttpRequest) -> HttpResponse {
-    HttpResponse::default()
-        .in_response_to(&req)
-        .with_code(CODE_404_NOT_FOUND)
+fn handle_post(req: HttpRequest, config: &Config) -> HttpResponse {
+    let rsp = HttpResponse::default().in_response_to(&req);
+    if req.path.starts_with("/files/") {
+        return handle_post_files(req, config);
+    }
+    rsp.with_code(CODE_404_NOT_FOUND)
+}
+
+fn handle_post_files(req: HttpRequest, config: &Config) -> HttpResponse {
+    let rsp = HttpResponse::default().in_response_to(&req);
+    let Some(directory) = config.directory.as_ref() else {
+        return rsp.with_code(CODE_500_INTERNAL_SERVER_ERROR);
+    };
+    let filename = req.path.strip_prefix("/files/");
+    if matches!(filename, None | Some("")) || filename.unwrap().split('/').any(|seg| seg == "..") {
+        return rsp.with_code(CODE_400_BAD_REQUEST);
+    }
+    let path = format!("{directory}/{}", filename.unwrap());
+    match fs::write(path, &req.body) {
+        Ok(()) => rsp.with_code(CODE_201_CREATED),
+        Err(_) => rsp.with_code(CODE_500_INTERNAL_SERVER_ERROR),
+    }
 }
 
 fn handle_echo(req: HttpRequest) -> HttpResponse {
@@ -133,26 +444,71 @@ fn handle_files(req: HttpRequest, config: &Config) -> HttpResponse {
     if config.directory.is_none() {
         return rsp.with_code(CODE_500_INTERNAL_SERVER_ERROR);
     }
-    let filename = req.path.strip_prefix("/files/");
-    if filename.is_none() {
+    let decoded_path = percent_decode(&req.path);
+    let subpath = decoded_path.strip_prefix("/files/");
+    if subpath.is_none() {
         return rsp.with_code(CODE_400_BAD_REQUEST);
     }
-    let filename = format!(
-        "{}/{}",
-        config.directory.as_ref().unwrap(),
-        filename.unwrap()
-    );
+    let subpath = subpath.unwrap();
+    let filename = format!("{}/{}", config.directory.as_ref().unwrap(), subpath);
+
+    let metadata = match fs::metadata(&filename) {
+        Ok(metadata) => metadata,
+        Err(_) => return rsp.with_code(CODE_404_NOT_FOUND),
+    };
+    if metadata.is_dir() {
+        return rsp
+            .with_code(CODE_200_OK)
+            .with_html_content(&directory_index_html(&filename, subpath));
+    }
+
+    let last_modified = metadata.modified().ok();
+
+    if let (Some(last_modified), Some(if_modified_since)) =
+        (last_modified, req.headers.get("If-Modified-Since"))
+    {
+        if let Some(since) = parse_http_date(if_modified_since) {
+            if since <= SystemTime::now() && truncate_to_secs(last_modified) <= since {
+                let mut rsp = rsp.with_code(CODE_304_NOT_MODIFIED);
+                rsp.headers
+                    .insert("Last-Modified".to_owned(), format_http_date(last_modified));
+                return rsp;
+            }
+        }
+    }
+
     let file_content = fs::read(filename);
     if file_content.is_err() {
         return rsp.with_code(CODE_404_NOT_FOUND);
     }
-    return rsp
-        .with_code(CODE_200_OK)
-        .with_binary_content(file_content.unwrap());
+    let file_content = file_content.unwrap();
+    let total = file_content.len();
+
+    let mut rsp = if let Some(range) = req.headers.get("Range") {
+        match parse_byte_range(range, total) {
+            Some((start, end)) => rsp
+                .with_code(CODE_206_PARTIAL_CONTENT)
+                .with_partial_content(file_content[start..=end].to_vec(), start, end, total),
+            None => {
+                let mut rsp = rsp.with_code(CODE_416_RANGE_NOT_SATISFIABLE);
+                rsp.headers
+                    .insert("Content-Range".to_owned(), format!("bytes */{total}"));
+                return rsp;
+            }
+        }
+    } else {
+        rsp.with_code(CODE_200_OK).with_binary_content(file_content)
+    };
+
+    if let Some(last_modified) = last_modified {
+        rsp.headers
+            .insert("Last-Modified".to_owned(), format_http_date(last_modified));
+    }
+    rsp
 }
 
 fn handle_user_agent(req: HttpRequest) -> HttpResponse {
-    match req.headers.get(&"User-Agent".to_owned()) {
+    match req.headers.get("User-Agent") {
         Some(user_agent) => HttpResponse::default()
             .in_response_to(&req)
             .with_code(CODE_200_OK)
@@ -163,62 +519,95 @@ fn handle_user_agent(req: HttpRequest) -> HttpResponse {
     }
 }
 
-fn read_http_request(stream: &mut TcpStream) -> Result<Vec<String>> {
-    let mut result = vec![];
-    let mut reader = BufReader::new(stream);
+/// Reads bytes one at a time until the blank line terminating the request
+/// line + headers is seen, capping the total at `MAX_HEADER_SECTION_BYTES`.
+/// Returns `Ok(None)` on a clean EOF before any bytes arrive (the peer
+/// simply closed an idle connection), and an error for anything else that
+/// keeps a complete header section from being assembled.
+fn read_header_section(reader: &mut BufReader<&TcpStream>) -> Result<Option<Vec<u8>>> {
+    let mut buf = Vec::new();
+    let mut byte = [0u8; 1];
     loop {
-        let mut line = String::new();
-        match reader.read_line(&mut line) {
-            Ok(_) => {
-                line = line
-                    .strip_suffix("\r\n")
-                    .ok_or(anyhow!("Unexpected suffix"))?
-                    .to_owned();
-                if line.is_empty() {
-                    break;
-                }
-                result.push(line);
-            }
-            _ => return Err(anyhow!("Failed reading http request")),
-        };
+        match reader.read(&mut byte)? {
+            0 if buf.is_empty() => return Ok(None),
+            0 => return Err(anyhow!("connection closed mid-request")),
+            _ => buf.push(byte[0]),
+        }
+        if buf.len() > MAX_HEADER_SECTION_BYTES {
+            return Err(anyhow!("header section exceeds maximum size"));
+        }
+        // A blank line may end in CRLF or a bare LF, as httparse accepts both.
+        if buf.ends_with(b"\n\n") || buf.ends_with(b"\n\r\n") {
+            return Ok(Some(buf));
+        }
     }
-    Ok(result)
 }
 
-fn read_request(stream: &mut TcpStream) -> Result<HttpRequest> {
-    let http_req_lines = read_http_request(stream)?;
-    let mut start_line = http_req_lines
-        .iter()
-        .next()
-        .context("start_line not found")?
-        .split(" ");
-    let method = start_line.next().context("method not found")?.to_owned();
-    let method = if method == "GET" {
-        HttpMethod::Get
-    } else if method == "POST" {
-        HttpMethod::Post
-    } else {
-        return Err(anyhow!("Unexpected method"));
+/// Reads and parses one request off `reader`, using `httparse` so the
+/// request line and headers can arrive in any of the ways real clients
+/// send them. Returns `Ok(None)` on EOF between requests, and an error for
+/// malformed input.
+fn read_request(reader: &mut BufReader<&TcpStream>) -> Result<Option<HttpRequest>> {
+    let Some(header_section) = read_header_section(reader)? else {
+        return Ok(None);
     };
-    let path = start_line.next().context("path not found")?.to_owned();
-    let version = start_line.next().context("version not found")?.to_owned();
-    let headers = http_req_lines
-        .iter()
-        .skip(1)
-        .filter_map(|line| {
-            line.split_once(": ")
-                .map(|(k, v)| (k.to_owned(), v.to_owned()))
-        })
-        .collect::<HashMap<_, _>>();
-    Ok(HttpRequest {
+
+    let mut header_storage = [httparse::EMPTY_HEADER; MAX_REQUEST_HEADERS];
+    let mut parsed = httparse::Request::new(&mut header_storage);
+    match parsed.parse(&header_section) {
+        Ok(httparse::Status::Complete(_)) => {}
+        _ => return Err(anyhow!("malformed request")),
+    }
+
+    let method = match parsed.method.context("method not found")? {
+        "GET" => HttpMethod::Get,
+        "POST" => HttpMethod::Post,
+        _ => return Err(anyhow!("unexpected method")),
+    };
+    let path = parsed.path.context("path not found")?.to_owned();
+    let version = format!("HTTP/1.{}", parsed.version.context("version not found")?);
+
+    let mut headers = Headers::default();
+    for header in parsed.headers.iter() {
+        headers.insert(
+            header.name.to_owned(),
+            String::from_utf8_lossy(header.value).into_owned(),
+        );
+    }
+
+    let body = match headers
+        .get("Content-Length")
+        .and_then(|len| len.parse::<usize>().ok())
+    {
+        Some(len) if len > MAX_BODY_BYTES => {
+            return Err(anyhow!("request body exceeds maximum size"))
+        }
+        Some(len) => {
+            let mut body = vec![0u8; len];
+            reader.read_exact(&mut body)?;
+            body
+        }
+        None => vec![],
+    };
+
+    Ok(Some(HttpRequest {
         method,
         path,
         version,
         headers,
-    })
+        body,
+    }))
 }
 
-fn write_response(stream: &mut TcpStream, response: HttpResponse) -> Result<()> {
+fn write_response(stream: &TcpStream, mut response: HttpResponse) -> Result<()> {
+    let mut stream = stream;
+    // Every response needs framing so a keep-alive peer knows where the body
+    // ends; responses with no explicit content (404/400/500/201/416, ...)
+    // would otherwise have neither a body nor a Content-Length.
+    response.headers.insert(
+        "Content-Length".to_owned(),
+        response.content.len().to_string(),
+    );
     let version = response.version;
     let code = response.code;
     let headers = response
@@ -231,20 +620,55 @@ fn write_response(stream: &mut TcpStream, response: HttpResponse) -> Result<()>
     Ok(())
 }
 
+/// Whether the connection should stay open for another request, per
+/// HTTP/1.1 keep-alive semantics: an explicit `Connection` header wins,
+/// otherwise HTTP/1.1 defaults to keep-alive and HTTP/1.0 defaults to close.
+fn should_keep_alive(req: &HttpRequest) -> bool {
+    match req.headers.get("Connection").map(|v| v.to_lowercase()) {
+        Some(v) if v == "close" => false,
+        Some(v) if v == "keep-alive" => true,
+        _ => req.version == "HTTP/1.1",
+    }
+}
+
+fn handle_connection(stream: &TcpStream, config: &Config) {
+    stream.set_read_timeout(Some(Duration::from_secs(30))).ok();
+    let mut reader = BufReader::new(stream);
+    loop {
+        let req = match read_request(&mut reader) {
+            Ok(Some(req)) => req,
+            Ok(None) => break,
+            Err(_) => {
+                let mut rsp = HttpResponse::default().with_code(CODE_400_BAD_REQUEST);
+                rsp.version = "HTTP/1.1".to_owned();
+                write_response(stream, rsp).ok();
+                break;
+            }
+        };
+        let keep_alive = should_keep_alive(&req);
+        let mut res = handle(req, config);
+        res.headers.insert(
+            "Connection".to_owned(),
+            if keep_alive { "keep-alive" } else { "close" }.to_owned(),
+        );
+        if write_response(stream, res).is_err() || !keep_alive {
+            break;
+        }
+    }
+}
+
 fn main() {
     let config = parse_config();
     let listener = TcpListener::bind("127.0.0.1:4221").unwrap();
     for stream in listener.incoming() {
         let config = config.clone();
         thread::spawn(move || match stream {
-            Ok(mut stream) => {
+            Ok(stream) => {
                 println!(
                     "accepted new connection on thread {:?}",
                     thread::current().id()
                 );
-                let req = read_request(&mut stream).expect("Failed reading request");
-                let res = handle(req, &config);
-                write_response(&mut stream, res).expect("Failed writing response");
+                handle_connection(&stream, &config);
             }
             Err(e) => {
                 println!("error: {}", e);